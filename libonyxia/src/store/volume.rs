@@ -4,6 +4,7 @@ use crate::needle::{Needle, NeedleBody, NeedleHeader};
 use crate::utils::{self, size::Size};
 
 use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::error::Error as StdError;
 use std::ffi::OsStr;
 use std::fmt::Display;
@@ -11,9 +12,449 @@ use std::fs::{File, OpenOptions};
 use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
 
+use crc32fast::Hasher as Crc32Hasher;
 use serde::Serialize;
 use serde_json::Deserializer;
 
+/// Marks the start of a self-describing needle record on disk.
+const NEEDLE_MAGIC: u8 = 0xa5;
+/// Pre-chunk0-6 record layout: no xattr block between the lengths and the
+/// body. Still readable (as a needle with no attrs) for volumes written
+/// before this version existed.
+const NEEDLE_VERSION_NO_XATTR: u8 = 2;
+/// Needle record layout version. Bump when the on-disk framing changes.
+const NEEDLE_VERSION: u8 = 3;
+/// `magic(1) + version(1) + codec(1) + key_len(2) + original_length(8) +
+/// stored_length(8)`, not counting the key itself. Shared by every version,
+/// since only what follows it has changed so far.
+const NEEDLE_RECORD_FIXED_LEN: u64 = 1 + 1 + 1 + 2 + 8 + 8;
+/// Extra `xattr_length(4)` field `NEEDLE_VERSION` adds in front of the xattr
+/// block, on top of [`NEEDLE_RECORD_FIXED_LEN`]. Absent in
+/// [`NEEDLE_VERSION_NO_XATTR`] records.
+const NEEDLE_XATTR_LEN_SIZE: u64 = 4;
+/// Total prefix length for a current-version record, not counting the key or
+/// the xattr block itself.
+const NEEDLE_RECORD_PREFIX_LEN: u64 = NEEDLE_RECORD_FIXED_LEN + NEEDLE_XATTR_LEN_SIZE;
+/// Trailing CRC32 of the xattr block (if any) plus the (possibly compressed)
+/// needle body, as stored on disk.
+const NEEDLE_CRC_LEN: u64 = 4;
+/// Sentinel length marking a tombstone (delete) record in the `.index` log
+/// rather than a real needle. No needle can legitimately have this length.
+const TOMBSTONE_LENGTH: u64 = u64::MAX;
+
+/// The only operation a WAL intent currently records. Kept as an explicit
+/// byte (rather than inferring it from context) so the record format has
+/// room for other operations later without a layout change.
+const WAL_OP_PUT: u8 = 1;
+/// Appended to the `.wal` file after the body and index entry an intent
+/// describes are both durably written. Its absence on replay means the
+/// write it describes never completed.
+const WAL_COMMIT_MARKER: u8 = 0xff;
+
+/// Superblock format version. Bump when the superblock layout changes.
+const SUPERBLOCK_VERSION: u8 = 1;
+/// `magic(4) + version(1) + volume_id(8) + max_length(8)`.
+const SUPERBLOCK_LEN: u64 = 4 + 1 + 8 + 8;
+/// Marks the start of a `.data` file.
+const DATA_SUPERBLOCK_MAGIC: [u8; 4] = *b"OXYD";
+/// Marks the start of a `.index` file.
+const INDEX_SUPERBLOCK_MAGIC: [u8; 4] = *b"OXYI";
+
+/// A versioned header at offset 0 of both the `.data` and `.index` files, so
+/// `Volume::open` can tell a current-format file from a pre-superblock one
+/// (and refuse anything claiming a format version we don't understand)
+/// without having to guess from content.
+struct Superblock {
+    volume_id: u64,
+    max_length: u64,
+}
+
+fn write_superblock(file: &mut File, magic: [u8; 4], superblock: &Superblock) -> Result<()> {
+    file.seek(SeekFrom::Start(0))?;
+    let mut buf = Vec::with_capacity(SUPERBLOCK_LEN as usize);
+    buf.extend_from_slice(&magic);
+    buf.push(SUPERBLOCK_VERSION);
+    buf.extend_from_slice(&superblock.volume_id.to_le_bytes());
+    buf.extend_from_slice(&superblock.max_length.to_le_bytes());
+    file.write_all(&buf)?;
+    Ok(())
+}
+
+/// Returns `Ok(None)` if the file doesn't start with `magic` at all (i.e. it
+/// predates the superblock and needs migrating), `Err` if it starts with
+/// `magic` but carries a format version we don't understand.
+fn read_superblock(file: &mut File, magic: [u8; 4]) -> Result<Option<Superblock>> {
+    file.seek(SeekFrom::Start(0))?;
+    let mut buf = [0u8; SUPERBLOCK_LEN as usize];
+    if file.read_exact(&mut buf).is_err() {
+        return Ok(None);
+    }
+    if buf[0..4] != magic {
+        return Ok(None);
+    }
+    if buf[4] != SUPERBLOCK_VERSION {
+        return Err(Error::volume(error::VolumeError::data_corruption(
+            0,
+            format!("unsupported superblock version: {}", buf[4]),
+        )));
+    }
+    Ok(Some(Superblock {
+        volume_id: u64::from_le_bytes(buf[5..13].try_into().unwrap()),
+        max_length: u64::from_le_bytes(buf[13..21].try_into().unwrap()),
+    }))
+}
+
+/// A single pending write, recorded in the `.wal` file before the needle body
+/// or its index entry are touched, so a crash between the two can be
+/// detected and rolled back on the next [`Volume::open`] instead of landing
+/// on the data-vs-index corruption `open()` used to reject outright.
+struct WalIntent {
+    path: String,
+    offset: u64,
+    length: u64,
+    codec: Codec,
+}
+
+fn open_wal<P: AsRef<Path>>(wal_filepath: P) -> Result<File> {
+    Ok(OpenOptions::new()
+        .read(true)
+        .write(true)
+        .append(true)
+        .create(true)
+        .open(wal_filepath)?)
+}
+
+/// Truncate `wal_file` and write `intent` as `op(1) + key_len(2) + key +
+/// offset(8) + length(8) + codec(1)`, fsyncing before returning so the
+/// intent is durable before the write it describes touches the data file.
+/// Truncating first (rather than just overwriting) means a crash mid-write
+/// leaves either an empty file or the new intent, never a mix of old and new.
+fn write_wal_intent(wal_file: &mut File, intent: &WalIntent) -> Result<()> {
+    wal_file.set_len(0)?;
+    let key = intent.path.as_bytes();
+    let mut buf = Vec::with_capacity(1 + 2 + key.len() + 16 + 1);
+    buf.push(WAL_OP_PUT);
+    buf.extend_from_slice(&(key.len() as u16).to_le_bytes());
+    buf.extend_from_slice(key);
+    buf.extend_from_slice(&intent.offset.to_le_bytes());
+    buf.extend_from_slice(&intent.length.to_le_bytes());
+    buf.push(intent.codec as u8);
+    wal_file.write_all(&buf)?;
+    wal_file.sync_all()?;
+    Ok(())
+}
+
+/// Append [`WAL_COMMIT_MARKER`] to `wal_file` and fsync, marking the most
+/// recently written intent as fully applied.
+fn commit_wal(wal_file: &mut File) -> Result<()> {
+    wal_file.write_all(&[WAL_COMMIT_MARKER])?;
+    wal_file.sync_all()?;
+    Ok(())
+}
+
+/// Truncate `wal_file` back to empty. Safe to call whether or not a pending
+/// intent is present.
+fn clear_wal(wal_file: &mut File) -> Result<()> {
+    wal_file.set_len(0)?;
+    wal_file.sync_all()?;
+    Ok(())
+}
+
+/// Read the pending intent out of `wal_file`, if any. Returns `None` for an
+/// empty WAL, a WAL whose intent already has a matching
+/// [`WAL_COMMIT_MARKER`] (nothing to roll back), or one with a truncated/
+/// unrecognized record (treated the same as every other truncated-tail read
+/// in this module: not an error, just nothing usable).
+fn read_wal_intent(wal_file: &mut File) -> Result<Option<WalIntent>> {
+    if wal_file.metadata()?.len() == 0 {
+        return Ok(None);
+    }
+    wal_file.seek(SeekFrom::Start(0))?;
+
+    let mut op_buf = [0u8; 1];
+    if wal_file.read_exact(&mut op_buf).is_err() || op_buf[0] != WAL_OP_PUT {
+        return Ok(None);
+    }
+    let mut key_len_buf = [0u8; 2];
+    if wal_file.read_exact(&mut key_len_buf).is_err() {
+        return Ok(None);
+    }
+    let key_len = u16::from_le_bytes(key_len_buf) as usize;
+    let mut key_buf = vec![0u8; key_len];
+    if wal_file.read_exact(&mut key_buf).is_err() {
+        return Ok(None);
+    }
+    let path = match String::from_utf8(key_buf) {
+        Ok(path) => path,
+        Err(_) => return Ok(None),
+    };
+    let mut fields_buf = [0u8; 17];
+    if wal_file.read_exact(&mut fields_buf).is_err() {
+        return Ok(None);
+    }
+    let offset = u64::from_le_bytes(fields_buf[0..8].try_into().unwrap());
+    let length = u64::from_le_bytes(fields_buf[8..16].try_into().unwrap());
+    let codec = match Codec::try_from(fields_buf[16]) {
+        Ok(codec) => codec,
+        Err(_) => return Ok(None),
+    };
+
+    let mut marker_buf = [0u8; 1];
+    if wal_file.read_exact(&mut marker_buf).is_ok() && marker_buf[0] == WAL_COMMIT_MARKER {
+        return Ok(None);
+    }
+    Ok(Some(WalIntent { path, offset, length, codec }))
+}
+
+/// Roll back whatever incomplete write is recorded in `wal_file`, if any:
+/// truncate `writable_volume` back to the intent's offset (discarding
+/// whatever body bytes a crash left dangling past it) and drop its path from
+/// `index_map`, unless the index entry already landed at that exact offset —
+/// in which case the body, its CRC, and the index entry all made it to disk
+/// before the crash, and only the WAL's own commit-marker fsync was lost, so
+/// the write is left intact instead of being discarded. Always clears the WAL
+/// afterwards so a stale record never lingers into the next write.
+fn replay_wal(
+    wal_file: &mut File,
+    writable_volume: &mut File,
+    index_map: &mut HashMap<String, RawIndex>,
+) -> Result<()> {
+    if let Some(intent) = read_wal_intent(wal_file)? {
+        let already_durable = index_map
+            .get(&intent.path)
+            .map(|index| index.offset as u64 == intent.offset)
+            .unwrap_or(false);
+        if !already_durable {
+            log::warn!(
+                "replaying uncommitted wal intent, rolling back: path {}, offset {}, length {}, codec {:?}",
+                intent.path,
+                intent.offset,
+                intent.length,
+                intent.codec
+            );
+            writable_volume.set_len(intent.offset)?;
+            writable_volume.sync_all()?;
+            index_map.remove(&intent.path);
+        }
+    }
+    clear_wal(wal_file)
+}
+
+/// Append one binary index record: `key_len(4) + key + volume_id(8) +
+/// offset(8) + length(8)`. `length == TOMBSTONE_LENGTH` marks a delete.
+fn write_index_entry(file: &mut File, path: &str, volume_id: u64, offset: u64, length: u64) -> Result<()> {
+    let key = path.as_bytes();
+    let mut buf = Vec::with_capacity(4 + key.len() + 24);
+    buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    buf.extend_from_slice(key);
+    buf.extend_from_slice(&volume_id.to_le_bytes());
+    buf.extend_from_slice(&offset.to_le_bytes());
+    buf.extend_from_slice(&length.to_le_bytes());
+    file.write_all(&buf)?;
+    Ok(())
+}
+
+/// Replay every binary index record in `reader` from the current position to
+/// EOF, folding tombstones into removals. Stops at the first short read
+/// rather than erroring, since a truncated trailing record is the expected
+/// shape of a crash mid-append.
+fn read_index_entries(reader: &mut impl Read) -> Result<(HashMap<String, RawIndex>, RawIndex)> {
+    let mut index_map = HashMap::new();
+    // Starts where the data file's own superblock ends, so a volume with no
+    // live entries (brand new, or fully tombstoned) reports a `last_index`
+    // consistent with an empty data file rather than one starting at offset 0.
+    let mut last_index = RawIndex::new(0, SUPERBLOCK_LEN as usize, 0);
+    loop {
+        let mut key_len_buf = [0u8; 4];
+        if reader.read_exact(&mut key_len_buf).is_err() {
+            break;
+        }
+        let key_len = u32::from_le_bytes(key_len_buf) as usize;
+
+        let mut key_buf = vec![0u8; key_len];
+        if reader.read_exact(&mut key_buf).is_err() {
+            break;
+        }
+        let path = match String::from_utf8(key_buf) {
+            Ok(path) => path,
+            Err(_) => break,
+        };
+
+        let mut fields_buf = [0u8; 24];
+        if reader.read_exact(&mut fields_buf).is_err() {
+            break;
+        }
+        let volume_id = u64::from_le_bytes(fields_buf[0..8].try_into().unwrap()) as usize;
+        let offset = u64::from_le_bytes(fields_buf[8..16].try_into().unwrap());
+        let length = u64::from_le_bytes(fields_buf[16..24].try_into().unwrap());
+
+        if length == TOMBSTONE_LENGTH {
+            index_map.remove(&path);
+            continue;
+        }
+        let raw_index = RawIndex::new(volume_id, offset as usize, length as usize);
+        last_index = raw_index;
+        index_map.insert(path, raw_index);
+    }
+    Ok((index_map, last_index))
+}
+
+/// Replay a pre-chunk0-4 append-only JSON `.index` log, for migration only.
+fn read_legacy_json_index_entries(
+    reader: impl Read,
+    volume_id: usize,
+) -> Result<(HashMap<String, RawIndex>, RawIndex)> {
+    let indexes_reader = Deserializer::from_reader(reader).into_iter::<Index>();
+    let mut index_map = HashMap::new();
+    // Legacy (pre-superblock) offsets start at 0, not SUPERBLOCK_LEN; the
+    // caller shifts this forward once the data file itself is migrated.
+    let mut last_index = RawIndex::new(volume_id, 0, 0);
+    for index_result in indexes_reader {
+        let index: Index = index_result?;
+        if index.length as u64 == TOMBSTONE_LENGTH {
+            index_map.remove(&index.path);
+            continue;
+        }
+        let raw_index = RawIndex::new(volume_id, index.offset, index.length);
+        last_index = raw_index;
+        index_map.insert(index.path, raw_index);
+    }
+    Ok((index_map, last_index))
+}
+
+/// Per-needle compression codec, stored as a single byte in the needle record
+/// header so a volume can freely mix codecs across needles.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub enum Codec {
+    None = 0,
+    Zstd = 1,
+    Lzma = 2,
+}
+
+impl Default for Codec {
+    fn default() -> Codec {
+        Codec::None
+    }
+}
+
+impl TryFrom<u8> for Codec {
+    type Error = Box<dyn StdError + Send + Sync>;
+
+    fn try_from(value: u8) -> std::result::Result<Codec, Self::Error> {
+        match value {
+            0 => Ok(Codec::None),
+            1 => Ok(Codec::Zstd),
+            2 => Ok(Codec::Lzma),
+            other => Err(format!("unknown needle codec id: {}", other).into()),
+        }
+    }
+}
+
+fn compress(codec: Codec, data: &[u8]) -> Result<Vec<u8>> {
+    match codec {
+        Codec::None => Ok(data.to_vec()),
+        Codec::Zstd => Ok(zstd::stream::encode_all(data, 0)?),
+        Codec::Lzma => {
+            use std::io::Write as _;
+            let mut encoder = xz2::write::XzEncoder::new(Vec::new(), 6);
+            encoder.write_all(data)?;
+            Ok(encoder.finish()?)
+        }
+    }
+}
+
+fn decompress(codec: Codec, data: &[u8], original_length: usize) -> Result<Vec<u8>> {
+    match codec {
+        Codec::None => Ok(data.to_vec()),
+        Codec::Zstd => {
+            let decoded = zstd::stream::decode_all(data)?;
+            Ok(decoded)
+        }
+        Codec::Lzma => {
+            use std::io::Read as _;
+            let mut decoder = xz2::read::XzDecoder::new(data);
+            // `original_length` comes straight from the on-disk record header;
+            // only use it as a capacity *hint*, capped well below anything a
+            // corrupted length field could otherwise force us to allocate
+            // up front. `read_to_end` still grows the buffer as needed, so
+            // this only affects how much we pre-reserve, not correctness.
+            let capacity_hint = original_length.min(16 * 1024 * 1024);
+            let mut out = Vec::with_capacity(capacity_hint);
+            decoder.read_to_end(&mut out)?;
+            Ok(out)
+        }
+    }
+}
+
+/// Encode a needle's xattrs as `count(4) + [key_len(2) + key + value_len(4) +
+/// value]*`, the same manual length-prefixed style used for index entries.
+/// Errors rather than truncating if a key or value doesn't fit its length
+/// field, mirroring the key-length check `write_needle_with` does for the
+/// needle path itself.
+fn encode_attrs(attrs: &HashMap<String, Vec<u8>>) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(&(attrs.len() as u32).to_le_bytes());
+    for (key, value) in attrs {
+        let key_bytes = key.as_bytes();
+        if key_bytes.len() > u16::MAX as usize {
+            return Err(Error::naive(format!(
+                "xattr key too long to store inline: {} bytes",
+                key_bytes.len()
+            )));
+        }
+        if value.len() > u32::MAX as usize {
+            return Err(Error::naive(format!(
+                "xattr value too long to store inline: {} bytes",
+                value.len()
+            )));
+        }
+        buf.extend_from_slice(&(key_bytes.len() as u16).to_le_bytes());
+        buf.extend_from_slice(key_bytes);
+        buf.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        buf.extend_from_slice(value);
+    }
+    Ok(buf)
+}
+
+/// Inverse of [`encode_attrs`]. Returns `Error::data_corruption` if `buf` is
+/// shorter than the entry count promises.
+fn decode_attrs(buf: &[u8]) -> Result<HashMap<String, Vec<u8>>> {
+    let corrupt = || Error::data_corruption("needle xattrs", "truncated xattr block");
+    if buf.len() < 4 {
+        return Err(corrupt());
+    }
+    let count = u32::from_le_bytes(buf[0..4].try_into().unwrap()) as usize;
+    // Each entry needs at least 6 bytes (key_len + value_len), so a count
+    // that couldn't possibly fit in `buf` is corruption, not just a large
+    // attribute set; reject it before `with_capacity` tries to honor it.
+    if count > buf.len() / 6 {
+        return Err(corrupt());
+    }
+    let mut attrs = HashMap::with_capacity(count);
+    let mut cursor = 4usize;
+    for _ in 0..count {
+        if buf.len() < cursor + 2 {
+            return Err(corrupt());
+        }
+        let key_len = u16::from_le_bytes(buf[cursor..cursor + 2].try_into().unwrap()) as usize;
+        cursor += 2;
+        if buf.len() < cursor + key_len + 4 {
+            return Err(corrupt());
+        }
+        let key = String::from_utf8(buf[cursor..cursor + key_len].to_vec()).map_err(|_| corrupt())?;
+        cursor += key_len;
+        let value_len = u32::from_le_bytes(buf[cursor..cursor + 4].try_into().unwrap()) as usize;
+        cursor += 4;
+        if buf.len() < cursor + value_len {
+            return Err(corrupt());
+        }
+        let value = buf[cursor..cursor + value_len].to_vec();
+        cursor += value_len;
+        attrs.insert(key, value);
+    }
+    Ok(attrs)
+}
+
 pub enum VolumeExtension {
     Index = 1,
     Data = 2,
@@ -53,7 +494,14 @@ pub struct Volume {
     pub max_length: u64,
     #[serde(skip_serializing)]
     pub index_file: File,
+    /// Write-ahead log for the single write `write_needle_with` may have in
+    /// flight; see [`WalIntent`]. Empty whenever no write is in progress.
+    #[serde(skip_serializing)]
+    wal_file: File,
     pub indexes: HashMap<String, RawIndex>,
+    /// Default codec applied by `write_needle`. Individual needles may still be
+    /// written with a different codec via `write_needle_with_codec`.
+    pub codec: Codec,
 }
 
 impl Display for Volume {
@@ -64,6 +512,12 @@ impl Display for Volume {
 
 impl Volume {
     pub fn new(dir: &Path, id: usize, size: Size) -> Result<Volume> {
+        Self::new_with_codec(dir, id, size, Codec::None)
+    }
+
+    /// Like [`Volume::new`], but sets the codec used by default when
+    /// `write_needle` is called on the returned volume.
+    pub fn new_with_codec(dir: &Path, id: usize, size: Size, codec: Codec) -> Result<Volume> {
         let volume_path: PathBuf = dir.join(format!("{}.data", id));
         let index_path: PathBuf = dir.join(format!("{}.index", id));
         if volume_path.exists() {
@@ -88,8 +542,19 @@ impl Volume {
                 "already exists",
             )));
         }
-        let (index_file, index_map, _) = Self::open_indexes(index_path, true)?;
-        let (readonly_file, writable_file) = Self::open_volumes(&volume_path, true)?;
+        let max_length: u64 = size.into();
+        let wal_path: PathBuf = dir.join(format!("{}.wal", id));
+        let wal_file = open_wal(&wal_path)?;
+        let (index_file, index_map, _, _) = Self::open_indexes(index_path, id, max_length, true)?;
+        let (readonly_file, mut writable_file) = Self::open_volumes(&volume_path, true)?;
+        write_superblock(
+            &mut writable_file,
+            DATA_SUPERBLOCK_MAGIC,
+            &Superblock {
+                volume_id: id as u64,
+                max_length,
+            },
+        )?;
         let current_length = writable_file.metadata()?.len();
         Ok(Volume {
             id,
@@ -100,9 +565,11 @@ impl Volume {
             writable_volume: writable_file,
             readonly_volume: readonly_file,
             current_length,
-            max_length: size.into(),
+            max_length,
             index_file,
+            wal_file,
             indexes: index_map,
+            codec,
         })
     }
 
@@ -130,27 +597,38 @@ impl Volume {
         let naive_volume_path_str = utils::strings::trim_suffix(volume_path_str, extension_str)?;
         let index_file_str = naive_volume_path_str.to_owned() + "index";
         let volume_file_str = naive_volume_path_str.to_owned() + "data";
+        let wal_file_str = naive_volume_path_str.to_owned() + "wal";
+        let max_length: u64 = size.into();
 
-        let (index_file, index_map, last_index) = Self::open_indexes(index_file_str, false)?;
-        let (readonly_file, writable_file) = Self::open_volumes(volume_file_str, false)?;
-        let current_length = writable_file.metadata()?.len();
-        if current_length != (last_index.offset + last_index.length) as u64 {
-            log::error!(
-                "volume data corruption. path: {}, current_length: {}, last_index.offset: {}, last_index.length: {}",
-                volume_path.display(),
-                current_length,
-                last_index.offset,
-                last_index.length
+        let data_migrated = Self::migrate_legacy_data_superblock(&volume_file_str, id, max_length)?;
+        let (readonly_file, mut writable_file) = Self::open_volumes(&volume_file_str, false)?;
+
+        let (mut index_file, mut index_map, mut last_index, index_was_legacy) =
+            Self::open_indexes(&index_file_str, id, max_length, false)?;
+        if data_migrated {
+            // The data file just grew a superblock, so every previously
+            // recorded offset needs to shift forward by the same amount.
+            log::warn!(
+                "shifting index offsets by {} bytes after data superblock migration: id {}",
+                SUPERBLOCK_LEN,
+                id
             );
-            return Err(Error::volume(error::VolumeError::data_corruption(
-                id,
-                format!(
-                    "volume current length: {}, last_index.offset: {}, last_index.length: {}",
-                    current_length, last_index.offset, last_index.length
-                ),
-            )));
+            for raw_index in index_map.values_mut() {
+                raw_index.offset += SUPERBLOCK_LEN as usize;
+            }
+            last_index.offset += SUPERBLOCK_LEN as usize;
         }
-        Ok(Volume {
+        if data_migrated || index_was_legacy {
+            // Persist the migrated/shifted index exactly once, regardless of
+            // which of the two conditions (or both) triggered it.
+            index_file = Self::rewrite_index_file(&index_file_str, id, max_length, &index_map)?;
+        }
+
+        let mut wal_file = open_wal(&wal_file_str)?;
+        replay_wal(&mut wal_file, &mut writable_file, &mut index_map)?;
+
+        let current_length = writable_file.metadata()?.len();
+        let mut volume = Volume {
             id,
             volume_path: volume_path
                 .to_str()
@@ -159,10 +637,162 @@ impl Volume {
             writable_volume: writable_file,
             readonly_volume: readonly_file,
             current_length,
-            max_length: size.into(),
+            max_length,
             index_file,
+            wal_file,
             indexes: index_map,
-        })
+            codec: Codec::None,
+        };
+        if current_length != (last_index.offset + last_index.length) as u64 {
+            log::error!(
+                "volume index out of sync with data, rebuilding from data file. path: {}, current_length: {}, last_index.offset: {}, last_index.length: {}",
+                volume_path.display(),
+                current_length,
+                last_index.offset,
+                last_index.length
+            );
+            volume.rebuild_index()?;
+        }
+        Ok(volume)
+    }
+
+    /// Rebuild `.index` from the `.data` file by walking self-describing needle
+    /// records from the end of the data superblock, verifying each record's
+    /// CRC32 as it goes. Stops cleanly at the first truncated or corrupt
+    /// record, truncating the `.data` file there and discarding everything
+    /// from that point on, so a half-written tail is dropped rather than
+    /// treated as a hard failure. Returns the number of needles recovered.
+    pub fn rebuild_index(&mut self) -> Result<usize> {
+        let mut reader =
+            std::io::BufReader::new(self.readonly_volume.try_clone()?);
+        reader.seek(SeekFrom::Start(SUPERBLOCK_LEN))?;
+
+        let mut index_map = HashMap::new();
+        let mut offset = SUPERBLOCK_LEN;
+        loop {
+            match Self::read_needle_record(&mut reader, offset)? {
+                Some((path, record_offset, record_length, next_offset)) => {
+                    index_map.insert(
+                        path,
+                        RawIndex::new(self.id, record_offset as usize, record_length as usize),
+                    );
+                    offset = next_offset;
+                }
+                None => {
+                    if offset != self.current_length {
+                        log::warn!(
+                            "rebuild_index: discarding tail from offset {} in {}",
+                            offset,
+                            self.volume_path
+                        );
+                        self.writable_volume.set_len(offset)?;
+                        self.writable_volume.sync_all()?;
+                    }
+                    break;
+                }
+            }
+        }
+
+        let index_path = Path::new(&self.volume_path).with_extension("index");
+        let index_path_str = index_path
+            .to_str()
+            .ok_or(Error::naive(format!("{:?} to string", index_path)))?;
+        let fresh_index_file =
+            Self::rewrite_index_file(index_path_str, self.id, self.max_length, &index_map)?;
+
+        let recovered = index_map.len();
+        self.current_length = offset;
+        self.indexes = index_map;
+        self.index_file = fresh_index_file;
+        Ok(recovered)
+    }
+
+    /// Read one self-describing needle record starting at `offset` from `reader`,
+    /// which must already be positioned there. On success returns the needle's
+    /// path, the record's start offset (`offset`, echoed back for convenience),
+    /// its total on-disk length, and the offset of the next record. Returns
+    /// `Ok(None)` for anything that isn't a complete, CRC-valid record (EOF,
+    /// truncated tail, bad magic/version, or a checksum mismatch) so callers can
+    /// treat it as "nothing more to read" rather than a hard error.
+    fn read_needle_record(
+        reader: &mut impl Read,
+        offset: u64,
+    ) -> Result<Option<(String, u64, u64, u64)>> {
+        let mut fixed = [0u8; 4]; // magic, version, codec, key_len(2) minus key_len itself below
+        if reader.read_exact(&mut fixed[..3]).is_err() {
+            return Ok(None);
+        }
+        let version = fixed[1];
+        if fixed[0] != NEEDLE_MAGIC || (version != NEEDLE_VERSION && version != NEEDLE_VERSION_NO_XATTR) {
+            return Ok(None);
+        }
+        let codec = match Codec::try_from(fixed[2]) {
+            Ok(codec) => codec,
+            Err(_) => return Ok(None),
+        };
+
+        let mut key_len_buf = [0u8; 2];
+        if reader.read_exact(&mut key_len_buf).is_err() {
+            return Ok(None);
+        }
+        let key_len = u16::from_le_bytes(key_len_buf) as usize;
+
+        let mut key_buf = vec![0u8; key_len];
+        if reader.read_exact(&mut key_buf).is_err() {
+            return Ok(None);
+        }
+        let path = match String::from_utf8(key_buf) {
+            Ok(path) => path,
+            Err(_) => return Ok(None),
+        };
+
+        let mut lens_buf = [0u8; 16];
+        if reader.read_exact(&mut lens_buf).is_err() {
+            return Ok(None);
+        }
+        let _original_length = u64::from_le_bytes(lens_buf[0..8].try_into().unwrap());
+        let stored_length = u64::from_le_bytes(lens_buf[8..16].try_into().unwrap());
+        let _ = codec;
+
+        let xattr_length: u64 = if version == NEEDLE_VERSION_NO_XATTR {
+            0
+        } else {
+            let mut xattr_len_buf = [0u8; NEEDLE_XATTR_LEN_SIZE as usize];
+            if reader.read_exact(&mut xattr_len_buf).is_err() {
+                return Ok(None);
+            }
+            u32::from_le_bytes(xattr_len_buf) as u64
+        };
+
+        let mut hasher = Crc32Hasher::new();
+        let mut remaining = xattr_length + stored_length;
+        let mut chunk = [0u8; 64 * 1024];
+        while remaining > 0 {
+            let want = remaining.min(chunk.len() as u64) as usize;
+            if reader.read_exact(&mut chunk[..want]).is_err() {
+                return Ok(None);
+            }
+            hasher.update(&chunk[..want]);
+            remaining -= want as u64;
+        }
+
+        let mut crc_buf = [0u8; NEEDLE_CRC_LEN as usize];
+        if reader.read_exact(&mut crc_buf).is_err() {
+            return Ok(None);
+        }
+        if hasher.finalize() != u32::from_le_bytes(crc_buf) {
+            log::warn!("rebuild_index: crc mismatch for {} at offset {}", path, offset);
+            return Ok(None);
+        }
+
+        let prefix_len = if version == NEEDLE_VERSION_NO_XATTR {
+            NEEDLE_RECORD_FIXED_LEN
+        } else {
+            NEEDLE_RECORD_PREFIX_LEN
+        };
+        let record_length = prefix_len + key_len as u64 + xattr_length + stored_length + NEEDLE_CRC_LEN;
+        let next_offset = offset + record_length;
+        Ok(Some((path, offset, record_length, next_offset)))
     }
 
     fn parse_volume_file_stem_name(volume_path: &Path) -> Result<usize> {
@@ -220,11 +850,62 @@ impl Volume {
         Ok((readonly_file, writable_file))
     }
 
+    /// If the `.data` file at `volume_file_path` doesn't already start with
+    /// the data superblock, it predates chunk0-4: stage a copy with the
+    /// superblock prepended and swap it in, the same stage-then-rename
+    /// approach [`Volume::compact`] uses, so migrating a volume close to its
+    /// `max_length` never needs to hold the whole thing in memory at once.
+    /// Returns whether a migration happened, so the caller can shift its
+    /// already-loaded index offsets by the same amount.
+    fn migrate_legacy_data_superblock(volume_file_path: &str, id: usize, max_length: u64) -> Result<bool> {
+        let mut file = OpenOptions::new().read(true).open(volume_file_path)?;
+        if read_superblock(&mut file, DATA_SUPERBLOCK_MAGIC)?.is_some() {
+            return Ok(false);
+        }
+        log::warn!(
+            "legacy data file without a superblock, migrating in place: id {}",
+            id
+        );
+        file.seek(SeekFrom::Start(0))?;
+
+        let staged_path = Path::new(volume_file_path).with_extension("data.migrate");
+        let mut staged_file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&staged_path)?;
+        write_superblock(
+            &mut staged_file,
+            DATA_SUPERBLOCK_MAGIC,
+            &Superblock {
+                volume_id: id as u64,
+                max_length,
+            },
+        )?;
+        std::io::copy(&mut file, &mut staged_file)?;
+        staged_file.sync_all()?;
+        drop(staged_file);
+        drop(file);
+
+        std::fs::rename(&staged_path, volume_file_path)?;
+        Ok(true)
+    }
+
+    /// Opens (or creates) the `.index` file and replays it into memory. The
+    /// returned bool is `true` when the file was still in the pre-chunk0-4
+    /// JSON format and has NOT yet been rewritten as binary — the caller is
+    /// responsible for persisting the migrated form once, after it has had a
+    /// chance to apply any other pending offset corrections (e.g. from a
+    /// sibling data-file superblock migration), so a legacy volume never gets
+    /// rewritten twice in one `open()`.
     fn open_indexes<P: AsRef<Path>>(
         filepath: P,
+        id: usize,
+        max_length: u64,
         new: bool,
-    ) -> Result<(File, HashMap<String, RawIndex>, RawIndex)> {
-        let index_file = OpenOptions::new()
+    ) -> Result<(File, HashMap<String, RawIndex>, RawIndex, bool)> {
+        let mut index_file = OpenOptions::new()
             .read(true)
             .write(true)
             .create(new)
@@ -233,25 +914,84 @@ impl Volume {
             .append(true)
             .open(filepath.as_ref())?;
 
-        let mut index_map = HashMap::new();
-
         if new {
-            return Ok((index_file, index_map, RawIndex::default()));
+            write_superblock(
+                &mut index_file,
+                INDEX_SUPERBLOCK_MAGIC,
+                &Superblock {
+                    volume_id: id as u64,
+                    max_length,
+                },
+            )?;
+            return Ok((index_file, HashMap::new(), RawIndex::default(), false));
         }
+
         let volume_id: usize = Self::parse_volume_file_stem_name(filepath.as_ref())?;
         let mut readonly_index_file = index_file.try_clone()?;
-        readonly_index_file.seek(SeekFrom::Start(0))?;
-        let reader = std::io::BufReader::new(readonly_index_file);
-        let indexes_reader = Deserializer::from_reader(reader).into_iter::<Index>();
-        let mut last_index = RawIndex::default();
-        for index_result in indexes_reader {
-            let index: Index = index_result?;
-            let raw_index = RawIndex::new(volume_id, index.offset, index.length);
-            last_index = raw_index;
-            index_map.insert(index.path, raw_index);
+        let superblock = read_superblock(&mut readonly_index_file, INDEX_SUPERBLOCK_MAGIC)?;
+
+        match superblock {
+            Some(superblock) => {
+                if superblock.volume_id != volume_id as u64 {
+                    return Err(Error::volume(error::VolumeError::data_corruption(
+                        volume_id,
+                        format!(
+                            "index superblock volume_id {} doesn't match filename {}",
+                            superblock.volume_id, volume_id
+                        ),
+                    )));
+                }
+                let mut reader = std::io::BufReader::new(readonly_index_file);
+                let (index_map, last_index) = read_index_entries(&mut reader)?;
+                Ok((index_file, index_map, last_index, false))
+            }
+            None => {
+                log::warn!(
+                    "legacy json index detected, migrating to binary format: {:?}",
+                    filepath.as_ref()
+                );
+                readonly_index_file.seek(SeekFrom::Start(0))?;
+                let reader = std::io::BufReader::new(readonly_index_file);
+                let (index_map, last_index) = read_legacy_json_index_entries(reader, volume_id)?;
+                Ok((index_file, index_map, last_index, true))
+            }
         }
+    }
 
-        Ok((index_file, index_map, last_index))
+    /// Truncate `index_file` and rewrite it from scratch as a superblock
+    /// followed by one binary entry per `index_map` value. Used to persist a
+    /// just-migrated or offset-shifted index exactly once.
+    fn rewrite_index_file(
+        index_path: &str,
+        id: usize,
+        max_length: u64,
+        index_map: &HashMap<String, RawIndex>,
+    ) -> Result<File> {
+        let mut fresh_index_file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(index_path)?;
+        write_superblock(
+            &mut fresh_index_file,
+            INDEX_SUPERBLOCK_MAGIC,
+            &Superblock {
+                volume_id: id as u64,
+                max_length,
+            },
+        )?;
+        for (path, raw_index) in index_map {
+            write_index_entry(
+                &mut fresh_index_file,
+                path,
+                raw_index.volume_id as u64,
+                raw_index.offset as u64,
+                raw_index.length as u64,
+            )?;
+        }
+        fresh_index_file.sync_all()?;
+        Ok(fresh_index_file)
     }
 
     fn can_write(&self, length: u64) -> bool {
@@ -263,84 +1003,369 @@ impl Volume {
     }
 
     pub fn write_needle(&mut self, path: &str, needle: Needle) -> Result<()> {
-        let length = needle.total_length() as usize;
-        if !self.can_write(length as u64) {
-            log::error!(
-                "couldn't write to the volume. id: {}, path: {}, writable: {}, max_length: {}, current_length: {}, todo: {}",
-                self.id,
-                self.volume_path,
-                self.writable(),
-                self.max_length,
-                self.current_length,
-               length
-            );
-            return Err(Error::volume(error::VolumeError::overflow(
-                self.id,
-                self.max_length,
-                self.current_length,
-                length as u64,
+        let codec = self.codec;
+        self.write_needle_with(path, needle, codec, HashMap::new())
+    }
+
+    /// Like [`Volume::write_needle`], but compresses the needle body with
+    /// `codec` instead of the volume's default. The codec id and both the
+    /// original (logical) and stored (on-disk) lengths are recorded in the
+    /// needle header so `read_needle` can pick the right decoder later,
+    /// regardless of what the volume's own default codec is at read time.
+    pub fn write_needle_with_codec(
+        &mut self,
+        path: &str,
+        needle: Needle,
+        codec: Codec,
+    ) -> Result<()> {
+        self.write_needle_with(path, needle, codec, HashMap::new())
+    }
+
+    /// Like [`Volume::write_needle`], but attaches `attrs` as inline xattrs
+    /// (content-type, original filename, user tags, ...) readable later via
+    /// [`Volume::getxattr`]/[`Volume::listxattr`] without touching the body.
+    pub fn write_needle_with_attrs(
+        &mut self,
+        path: &str,
+        needle: Needle,
+        attrs: HashMap<String, Vec<u8>>,
+    ) -> Result<()> {
+        let codec = self.codec;
+        self.write_needle_with(path, needle, codec, attrs)
+    }
+
+    fn write_needle_with(
+        &mut self,
+        path: &str,
+        needle: Needle,
+        codec: Codec,
+        attrs: HashMap<String, Vec<u8>>,
+    ) -> Result<()> {
+        let original_length = needle.total_length() as u64;
+        let key = path.as_bytes();
+        if key.len() > u16::MAX as usize {
+            return Err(Error::naive(format!(
+                "key too long to store inline: {} bytes",
+                key.len()
             )));
         }
-        let mut received_length = 0usize;
-        let mut writable_volume = self.writable_volume.try_clone()?;
-        writable_volume.seek(SeekFrom::Start(self.current_length))?;
+        let xattr_block = encode_attrs(&attrs)?;
+        let xattr_length = xattr_block.len() as u64;
 
-        let mut writer = BufWriter::new(writable_volume);
+        if codec == Codec::None {
+            // No compression means the body's size on disk is exactly its
+            // logical size, known up front from `needle.total_length()`, so
+            // there's no need to materialize it in memory first: stream each
+            // chunk straight to the writer and fold it into the CRC as it
+            // goes, same as before compression support existed.
+            let record_length = NEEDLE_RECORD_PREFIX_LEN
+                + key.len() as u64
+                + xattr_length
+                + original_length
+                + NEEDLE_CRC_LEN;
+            self.check_can_write(path, record_length)?;
+            let record_offset = self.current_length;
+            self.begin_wal_intent(path, record_offset, record_length, codec)?;
+
+            let mut writable_volume = self.writable_volume.try_clone()?;
+            writable_volume.seek(SeekFrom::Start(record_offset))?;
+            let mut writer = BufWriter::new(writable_volume);
+            Self::write_record_prefix(
+                &mut writer,
+                codec,
+                key,
+                original_length,
+                original_length,
+                xattr_length,
+            )?;
+
+            let mut hasher = Crc32Hasher::new();
+            hasher.update(&xattr_block);
+            writer.write_all(&xattr_block)?;
+
+            let mut received_length = 0u64;
+            for data in needle.into_iter() {
+                let data = data?;
+                log::debug!("data: {:?}", data);
+                received_length += data.as_ref().len() as u64;
+                hasher.update(data.as_ref());
+                writer.write_all(data.as_ref())?;
+            }
+            if received_length != original_length {
+                log::error!(
+                    "mismatched needle length. received: {}, announced: {}",
+                    received_length,
+                    original_length
+                );
+                return Err(Error::volume(error::VolumeError::write_length_mismatch(
+                    self.id,
+                    path,
+                    original_length as usize,
+                    received_length as usize,
+                )));
+            }
+            writer.write_all(&hasher.finalize().to_le_bytes())?;
+            writer.flush()?;
+            writer.get_ref().sync_all()?;
+
+            return self.finish_write(path, record_offset, record_length);
+        }
 
-        let needle_iter = needle.into_iter();
-        for data in needle_iter {
+        let mut original = Vec::with_capacity(original_length as usize);
+        for data in needle.into_iter() {
             let data = data?;
             log::debug!("data: {:?}", data);
-            received_length += data.len();
-            writer.write_all(data.as_ref())?;
+            original.extend_from_slice(data.as_ref());
         }
-
-        if received_length != length {
+        if original.len() as u64 != original_length {
             log::error!(
                 "mismatched needle length. received: {}, announced: {}",
-                received_length,
-                length
+                original.len(),
+                original_length
             );
             return Err(Error::volume(error::VolumeError::write_length_mismatch(
                 self.id,
                 path,
-                length,
-                received_length,
+                original_length as usize,
+                original.len(),
             )));
         }
+        let stored = compress(codec, &original)?;
+        let stored_length = stored.len() as u64;
 
-        // write index
-        // TODO: supports write-ahead log
+        let record_length = NEEDLE_RECORD_PREFIX_LEN
+            + key.len() as u64
+            + xattr_length
+            + stored_length
+            + NEEDLE_CRC_LEN;
+        self.check_can_write(path, record_length)?;
+        let record_offset = self.current_length;
+        self.begin_wal_intent(path, record_offset, record_length, codec)?;
 
-        let index = Index::new(
-            path.to_owned(),
-            self.id,
-            self.current_length as usize,
-            length,
-        );
-        self.index_file
-            .write_all(serde_json::to_string(&index)?.as_bytes())?;
-        self.current_length += length as u64;
+        let mut writable_volume = self.writable_volume.try_clone()?;
+        writable_volume.seek(SeekFrom::Start(record_offset))?;
+        let mut writer = BufWriter::new(writable_volume);
+        Self::write_record_prefix(
+            &mut writer,
+            codec,
+            key,
+            original_length,
+            stored_length,
+            xattr_length,
+        )?;
+
+        let mut hasher = Crc32Hasher::new();
+        hasher.update(&xattr_block);
+        hasher.update(&stored);
+        writer.write_all(&xattr_block)?;
+        writer.write_all(&stored)?;
+        writer.write_all(&hasher.finalize().to_le_bytes())?;
+        writer.flush()?;
+        // flush() only pushes the buffer through write(2); fsync it so the
+        // body is actually durable before the WAL can say so.
+        writer.get_ref().sync_all()?;
+
+        self.finish_write(path, record_offset, record_length)
+    }
+
+    /// Error out if `record_length` more bytes wouldn't fit in the volume.
+    fn check_can_write(&self, path: &str, record_length: u64) -> Result<()> {
+        if !self.can_write(record_length) {
+            log::error!(
+                "couldn't write to the volume. id: {}, path: {}, writable: {}, max_length: {}, current_length: {}, todo: {}",
+                self.id,
+                path,
+                self.writable(),
+                self.max_length,
+                self.current_length,
+                record_length
+            );
+            return Err(Error::volume(error::VolumeError::overflow(
+                self.id,
+                self.max_length,
+                self.current_length,
+                record_length,
+            )));
+        }
+        Ok(())
+    }
+
+    /// Record the write as an intent before touching the data file, so a
+    /// crash between the body landing and the index entry landing can be
+    /// rolled back on the next open() instead of leaving current_length out
+    /// of sync with the index.
+    fn begin_wal_intent(&mut self, path: &str, offset: u64, length: u64, codec: Codec) -> Result<()> {
+        write_wal_intent(
+            &mut self.wal_file,
+            &WalIntent {
+                path: path.to_owned(),
+                offset,
+                length,
+                codec,
+            },
+        )
+    }
+
+    /// Write the fixed-size record prefix (magic, version, codec, key, and
+    /// the length fields) shared by both the streamed and buffered write
+    /// paths.
+    fn write_record_prefix(
+        writer: &mut BufWriter<File>,
+        codec: Codec,
+        key: &[u8],
+        original_length: u64,
+        stored_length: u64,
+        xattr_length: u64,
+    ) -> Result<()> {
+        let mut prefix = Vec::with_capacity(NEEDLE_RECORD_PREFIX_LEN as usize + key.len());
+        prefix.push(NEEDLE_MAGIC);
+        prefix.push(NEEDLE_VERSION);
+        prefix.push(codec as u8);
+        prefix.extend_from_slice(&(key.len() as u16).to_le_bytes());
+        prefix.extend_from_slice(key);
+        prefix.extend_from_slice(&original_length.to_le_bytes());
+        prefix.extend_from_slice(&stored_length.to_le_bytes());
+        prefix.extend_from_slice(&(xattr_length as u32).to_le_bytes());
+        writer.write_all(&prefix)?;
+        Ok(())
+    }
+
+    /// Persist the index entry for a just-written record, fsync it, update
+    /// in-memory bookkeeping, and mark the WAL intent committed. Shared tail
+    /// of both `write_needle_with` branches once the body itself is durable.
+    fn finish_write(&mut self, path: &str, record_offset: u64, record_length: u64) -> Result<()> {
+        write_index_entry(
+            &mut self.index_file,
+            path,
+            self.id as u64,
+            record_offset,
+            record_length,
+        )?;
+        self.index_file.sync_all()?;
+        self.current_length += record_length;
         self.indexes.insert(
             path.to_owned(),
-            RawIndex::new(index.volume_id, index.offset, index.length),
+            RawIndex::new(self.id, record_offset as usize, record_length as usize),
         );
+
+        // Body and index entry are both durable now; mark the intent
+        // complete so a future open() won't roll it back.
+        commit_wal(&mut self.wal_file)
+    }
+
+    /// Delete a needle by path. Appends a tombstone record to the `.index`
+    /// log and drops the path from the in-memory index immediately; the
+    /// needle's bytes stay in the `.data` file until the next [`Volume::compact`].
+    pub fn delete(&mut self, path: &str) -> Result<()> {
+        if !self.indexes.contains_key(path) {
+            return Err(Error::not_found(format!("not in indexes: {}", path)));
+        }
+        write_index_entry(&mut self.index_file, path, self.id as u64, 0, TOMBSTONE_LENGTH)?;
+        // Without this, a crash right after delete() returns can lose the
+        // tombstone while the in-memory index has already forgotten the
+        // path, so the next open() replays the stale live entry instead.
+        self.index_file.sync_all()?;
+        self.indexes.remove(path);
         Ok(())
     }
 
-    pub fn get<K>(&self, path: K) -> Result<Needle>
-    where
-        K: Into<String> + Display,
-    {
-        let path = path.into();
-        let index: RawIndex = self
+    /// Rewrite the volume's `.data`/`.index` pair, keeping only needles still
+    /// present in `indexes` (i.e. skipping tombstoned and overwritten
+    /// entries), then atomically swap them in. Returns the number of bytes
+    /// reclaimed. `dir` is the directory the new pair is staged in before the
+    /// swap; it should be on the same filesystem as the volume for the rename
+    /// to be atomic.
+    pub fn compact(&mut self, dir: &Path) -> Result<u64> {
+        let staged_data_path = dir.join(format!("{}.data.compact", self.id));
+        let staged_index_path = dir.join(format!("{}.index.compact", self.id));
+
+        let mut staged_data_file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&staged_data_path)?;
+        let mut staged_index_file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&staged_index_path)?;
+        write_superblock(
+            &mut staged_data_file,
+            DATA_SUPERBLOCK_MAGIC,
+            &Superblock {
+                volume_id: self.id as u64,
+                max_length: self.max_length,
+            },
+        )?;
+        write_superblock(
+            &mut staged_index_file,
+            INDEX_SUPERBLOCK_MAGIC,
+            &Superblock {
+                volume_id: self.id as u64,
+                max_length: self.max_length,
+            },
+        )?;
+
+        let mut live: Vec<(String, RawIndex)> = self
             .indexes
-            .get(&path)
-            .ok_or(Error::not_found(format!(
-                "not in indexes: {}, indexes: {:?}",
-                path, self.indexes
-            )))?
-            .clone();
+            .iter()
+            .map(|(path, raw_index)| (path.clone(), raw_index.clone()))
+            .collect();
+        live.sort_by_key(|(_, raw_index)| raw_index.offset);
+
+        let mut reader = self.readonly_volume.try_clone()?;
+        let mut new_offset = SUPERBLOCK_LEN;
+        let mut new_indexes = HashMap::with_capacity(live.len());
+        for (path, raw_index) in live {
+            reader.seek(SeekFrom::Start(raw_index.offset as u64))?;
+            let mut record = vec![0u8; raw_index.length];
+            reader.read_exact(&mut record)?;
+            staged_data_file.write_all(&record)?;
+
+            write_index_entry(
+                &mut staged_index_file,
+                &path,
+                self.id as u64,
+                new_offset,
+                raw_index.length as u64,
+            )?;
+            new_indexes.insert(
+                path,
+                RawIndex::new(self.id, new_offset as usize, raw_index.length),
+            );
+            new_offset += raw_index.length as u64;
+        }
+        staged_data_file.sync_all()?;
+        staged_index_file.sync_all()?;
+
+        let bytes_reclaimed = self.current_length.saturating_sub(new_offset);
+
+        let data_path = PathBuf::from(&self.volume_path);
+        let index_path = data_path.with_extension("index");
+        std::fs::rename(&staged_data_path, &data_path)?;
+        std::fs::rename(&staged_index_path, &index_path)?;
+
+        let (readonly_file, writable_file) = Self::open_volumes(&data_path, false)?;
+        let index_file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .truncate(false)
+            .append(true)
+            .open(&index_path)?;
+
+        self.writable_volume = writable_file;
+        self.readonly_volume = readonly_file;
+        self.index_file = index_file;
+        self.current_length = new_offset;
+        self.indexes = new_indexes;
+
+        Ok(bytes_reclaimed)
+    }
+
+    /// Sanity-check an already-looked-up index against `current_length`.
+    fn check_index_bounds(&self, path: &str, index: RawIndex) -> Result<RawIndex> {
         if ((index.offset + index.length) as u64) > self.current_length {
             log::error!(
                 "volume data corruption. path: {}, volume_length: {}, index.offset: {}, index.length: {}",
@@ -351,9 +1376,122 @@ impl Volume {
             );
             return Err(Error::data_corruption(path, "index out of current length"));
         }
+        Ok(index)
+    }
+
+    /// Look up `path` in `indexes` and sanity-check it against
+    /// `current_length`, without touching the `.data` file. Shared by
+    /// [`Volume::get`] and [`Volume::get_many`], and usable on its own by
+    /// callers (e.g. an async wrapper) that want to resolve the index while
+    /// holding a lock, then do the actual read afterwards.
+    pub fn resolve_index(&self, path: &str) -> Result<RawIndex> {
+        let index: RawIndex = self
+            .indexes
+            .get(path)
+            .ok_or(Error::not_found(format!(
+                "not in indexes: {}, indexes: {:?}",
+                path, self.indexes
+            )))?
+            .clone();
+        self.check_index_bounds(path, index)
+    }
+
+    pub fn get<K>(&self, path: K) -> Result<Needle>
+    where
+        K: Into<String> + Display,
+    {
+        let path = path.into();
+        let index = self.resolve_index(&path)?;
         Ok(self.read_needle(&index)?)
     }
 
+    /// Batched [`Volume::get`]. Resolves every path against `indexes` up
+    /// front, sorts the hits by ascending data-file offset to minimize seek
+    /// thrashing, then reads them in that order. Paths with no entry are
+    /// silently omitted from the result rather than failing the whole batch.
+    pub fn get_many<K>(&self, paths: &[K]) -> Result<HashMap<String, Needle>>
+    where
+        K: Into<String> + Display + Clone,
+    {
+        let mut hits: Vec<(String, RawIndex)> = Vec::with_capacity(paths.len());
+        for path in paths {
+            let path: String = path.clone().into();
+            if let Some(index) = self.indexes.get(&path).cloned() {
+                hits.push((path.clone(), self.check_index_bounds(&path, index)?));
+            }
+        }
+        hits.sort_by_key(|(_, index)| index.offset);
+
+        let mut needles = HashMap::with_capacity(hits.len());
+        for (path, index) in hits {
+            let needle = self.read_needle(&index)?;
+            needles.insert(path, needle);
+        }
+        Ok(needles)
+    }
+
+    /// Read just the xattrs attached to the needle at `path`, without
+    /// streaming its body. Returns `None` if `name` wasn't set (or the needle
+    /// predates chunk0-6 and carries no xattrs at all).
+    pub fn getxattr(&self, path: &str, name: &str) -> Result<Option<Vec<u8>>> {
+        let index = self.resolve_index(path)?;
+        Ok(Self::read_needle_attrs(&self.readonly_volume, &index)?.remove(name))
+    }
+
+    /// List the xattr names attached to the needle at `path`, without
+    /// streaming its body.
+    pub fn listxattr(&self, path: &str) -> Result<Vec<String>> {
+        let index = self.resolve_index(path)?;
+        Ok(Self::read_needle_attrs(&self.readonly_volume, &index)?
+            .keys()
+            .cloned()
+            .collect())
+    }
+
+    /// Read and decode the xattr block of the record at `index.offset`,
+    /// stopping well short of the body. Pre-chunk0-6 ([`NEEDLE_VERSION_NO_XATTR`])
+    /// records have no xattr block and decode to an empty map.
+    fn read_needle_attrs(file: &File, index: &RawIndex) -> Result<HashMap<String, Vec<u8>>> {
+        let mut file = file.try_clone()?;
+        file.seek(SeekFrom::Start(index.offset as u64))?;
+        let mut fixed = [0u8; 3];
+        file.read_exact(&mut fixed)?;
+        let version = fixed[1];
+        if fixed[0] != NEEDLE_MAGIC || (version != NEEDLE_VERSION && version != NEEDLE_VERSION_NO_XATTR) {
+            return Err(Error::data_corruption(
+                format!("offset {}", index.offset),
+                "bad needle magic/version",
+            ));
+        }
+        if version == NEEDLE_VERSION_NO_XATTR {
+            return Ok(HashMap::new());
+        }
+
+        let mut key_len_buf = [0u8; 2];
+        file.read_exact(&mut key_len_buf)?;
+        let key_len = u16::from_le_bytes(key_len_buf) as i64;
+        file.seek(SeekFrom::Current(key_len))?;
+
+        let mut lens_buf = [0u8; 16];
+        file.read_exact(&mut lens_buf)?; // original_length, stored_length; unused here
+
+        let mut xattr_len_buf = [0u8; NEEDLE_XATTR_LEN_SIZE as usize];
+        file.read_exact(&mut xattr_len_buf)?;
+        let xattr_length = u32::from_le_bytes(xattr_len_buf) as usize;
+        // The xattr block can't be larger than the record itself; catch a
+        // corrupted length field before trusting it to size an allocation.
+        if xattr_length > index.length {
+            return Err(Error::data_corruption(
+                format!("offset {}", index.offset),
+                "xattr length exceeds record length",
+            ));
+        }
+
+        let mut xattr_block = vec![0u8; xattr_length];
+        file.read_exact(&mut xattr_block)?;
+        decode_attrs(&xattr_block)
+    }
+
     pub fn read_needle_header(file: &mut File, offset: usize) -> Result<NeedleHeader> {
         let mut buffer = Vec::with_capacity(4);
         buffer.resize(4, 0 as u8);
@@ -365,13 +1503,135 @@ impl Volume {
 
     pub fn read_needle_body() {}
 
+    /// Read the fixed-size prefix of a needle record at `offset` (magic,
+    /// version, codec, key) and return the codec plus the offset where the
+    /// (possibly compressed) body starts, the original logical length, and
+    /// the stored on-disk length.
+    fn read_needle_record_header(
+        file: &mut File,
+        offset: u64,
+    ) -> Result<(Codec, u64, u64, u64)> {
+        file.seek(SeekFrom::Start(offset))?;
+        let mut fixed = [0u8; 3];
+        file.read_exact(&mut fixed)?;
+        let version = fixed[1];
+        if fixed[0] != NEEDLE_MAGIC || (version != NEEDLE_VERSION && version != NEEDLE_VERSION_NO_XATTR) {
+            return Err(Error::data_corruption(
+                format!("offset {}", offset),
+                "bad needle magic/version",
+            ));
+        }
+        let codec = Codec::try_from(fixed[2])?;
+
+        let mut key_len_buf = [0u8; 2];
+        file.read_exact(&mut key_len_buf)?;
+        let key_len = u16::from_le_bytes(key_len_buf) as u64;
+        file.seek(SeekFrom::Current(key_len as i64))?;
+
+        let mut lens_buf = [0u8; 16];
+        file.read_exact(&mut lens_buf)?;
+        let original_length = u64::from_le_bytes(lens_buf[0..8].try_into().unwrap());
+        let stored_length = u64::from_le_bytes(lens_buf[8..16].try_into().unwrap());
+
+        let (prefix_len, xattr_length) = if version == NEEDLE_VERSION_NO_XATTR {
+            (NEEDLE_RECORD_FIXED_LEN, 0u64)
+        } else {
+            let mut xattr_len_buf = [0u8; NEEDLE_XATTR_LEN_SIZE as usize];
+            file.read_exact(&mut xattr_len_buf)?;
+            (NEEDLE_RECORD_PREFIX_LEN, u32::from_le_bytes(xattr_len_buf) as u64)
+        };
+
+        let body_offset = offset + prefix_len + key_len + xattr_length;
+        Ok((codec, body_offset, original_length, stored_length))
+    }
+
     pub fn read_needle(&self, index: &RawIndex) -> Result<Needle> {
-        let mut readonly_volume = self.readonly_volume.try_clone()?;
-        let needle_header = Self::read_needle_header(&mut readonly_volume, index.offset)?;
-        if needle_header.body_length as usize != index.length - 4 {
+        Self::read_needle_from_file(&self.readonly_volume, index)
+    }
+
+    /// Like [`Volume::read_needle`], but takes an already-open handle onto
+    /// the `.data` file instead of borrowing `self`. This is the part of a
+    /// read that actually touches disk; callers that only need a brief lock
+    /// to resolve a [`RawIndex`] (e.g. an async wrapper around a volume
+    /// shared behind a lock) can clone the file handle, drop the lock, and
+    /// run this against the clone without holding anything for the duration
+    /// of the I/O.
+    pub fn read_needle_from_file(file: &File, index: &RawIndex) -> Result<Needle> {
+        let mut readonly_volume = file.try_clone()?;
+        let (codec, body_offset, original_length, stored_length) =
+            Self::read_needle_record_header(&mut readonly_volume, index.offset as u64)?;
+
+        if codec != Codec::None {
+            // stored_length comes straight from the on-disk record header; a
+            // corrupted record could otherwise claim an arbitrary length and
+            // force an unbounded allocation here. It can never legitimately
+            // exceed the record's own total length.
+            if stored_length > index.length as u64 {
+                return Err(Error::data_corruption(
+                    format!("offset {}", index.offset),
+                    "stored length exceeds record length",
+                ));
+            }
+
+            const INLINE_THRESHOLD: u64 = 1024 * 1024;
+            if stored_length <= INLINE_THRESHOLD {
+                readonly_volume.seek(SeekFrom::Start(body_offset))?;
+                let mut stored = vec![0u8; stored_length as usize];
+                readonly_volume.read_exact(&mut stored)?;
+                let original = decompress(codec, &stored, original_length as usize)?;
+                let needle_header: NeedleHeader = original[0..4].to_vec().into();
+                let body = bytes::Bytes::from(original[4..].to_vec());
+                return Ok(Needle {
+                    header: needle_header,
+                    body: NeedleBody::SinglePart(body),
+                });
+            }
+
+            // Large compressed bodies: decode through a streaming decoder
+            // fed straight from the file instead of buffering the whole
+            // compressed+decompressed body in memory, mirroring the
+            // chunked MultiParts path the uncompressed branch below uses.
+            let mut compressed_reader = file.try_clone()?;
+            compressed_reader.seek(SeekFrom::Start(body_offset))?;
+            let bounded = compressed_reader.take(stored_length);
+            let mut decoder: Box<dyn Read + Send> = match codec {
+                Codec::Zstd => Box::new(zstd::stream::read::Decoder::new(bounded)?),
+                Codec::Lzma => Box::new(xz2::read::XzDecoder::new(bounded)),
+                Codec::None => unreachable!(),
+            };
+            let mut header_buf = [0u8; 4];
+            decoder.read_exact(&mut header_buf)?;
+            let needle_header: NeedleHeader = header_buf.to_vec().into();
+
+            let (tx, rx) = std::sync::mpsc::sync_channel(1);
+            std::thread::spawn(move || {
+                let mut buffer = vec![0u8; 1024 * 1024];
+                loop {
+                    match decoder.read(&mut buffer) {
+                        Ok(0) => break,
+                        Ok(n) => {
+                            if tx.send(Ok(bytes::Bytes::copy_from_slice(&buffer[..n]))).is_err() {
+                                return;
+                            }
+                        }
+                        Err(e) => {
+                            let _ = tx.send(Err(Error::io(e)));
+                            return;
+                        }
+                    }
+                }
+            });
+            return Ok(Needle {
+                header: needle_header,
+                body: NeedleBody::MultiParts(rx),
+            });
+        }
+
+        let needle_header = Self::read_needle_header(&mut readonly_volume, body_offset as usize)?;
+        if needle_header.body_length as u64 != stored_length - 4 {
             log::error!(
-                "length from index: {}, length from needle header: {}",
-                index.length,
+                "length from header: {}, length from needle header: {}",
+                stored_length,
                 needle_header.body_length
             );
         }
@@ -380,11 +1640,11 @@ impl Volume {
         } else {
             needle_header.body_length as usize
         };
-        readonly_volume.seek(std::io::SeekFrom::Start(index.offset as u64 + 4))?;
+        readonly_volume.seek(std::io::SeekFrom::Start(body_offset as u64 + 4))?;
         let mut buffer = Vec::with_capacity(batch_size);
         buffer.resize(batch_size, 0 as u8);
-        let mut readonly_volume = self.readonly_volume.try_clone()?;
-        if index.length <= 1024 * 1024 {
+        let mut readonly_volume = file.try_clone()?;
+        if stored_length <= 1024 * 1024 {
             readonly_volume.read_exact(&mut buffer)?;
             return Ok(Needle {
                 header: needle_header,
@@ -393,7 +1653,7 @@ impl Volume {
         }
         // TODO: using thread pool
         let (tx, rx) = std::sync::mpsc::sync_channel(1);
-        let mut remains = index.length;
+        let mut remains = stored_length as usize;
         std::thread::spawn(move || {
             while remains > 0 {
                 let current = match readonly_volume.read(&mut buffer) {
@@ -435,6 +1695,35 @@ impl Volume {
 
 #[cfg(test)]
 mod test {
+    use super::{Needle, NeedleBody};
+
+    /// Build a minimal single-part needle whose on-disk body is exactly
+    /// `body`, matching the layout `read_needle_from_file`'s uncompressed
+    /// branch expects back: a 4-byte little-endian body-length header
+    /// followed by the body bytes.
+    fn test_needle(body: &[u8]) -> Needle {
+        let header_bytes = (body.len() as u32).to_le_bytes().to_vec();
+        Needle {
+            header: header_bytes.into(),
+            body: NeedleBody::SinglePart(bytes::Bytes::from(body.to_vec())),
+        }
+    }
+
+    /// Drain a needle's body into a plain `Vec<u8>`, regardless of whether
+    /// it came back as a single buffer or a streamed channel.
+    fn needle_body(needle: Needle) -> Vec<u8> {
+        match needle.body {
+            NeedleBody::SinglePart(bytes) => bytes.to_vec(),
+            NeedleBody::MultiParts(rx) => {
+                let mut out = Vec::new();
+                for chunk in rx {
+                    out.extend_from_slice(chunk.unwrap().as_ref());
+                }
+                out
+            }
+        }
+    }
+
     #[test]
     fn read_json_from_file() {
         use super::Index;
@@ -470,4 +1759,325 @@ mod test {
             assert_eq!(result[i], indexes[i]);
         }
     }
+
+    #[test]
+    fn read_needle_record_checks_crc_and_stops_cleanly_on_a_truncated_tail() {
+        use super::{Codec, Volume, NEEDLE_MAGIC, NEEDLE_VERSION};
+        use crc32fast::Hasher as Crc32Hasher;
+        use std::io::Cursor;
+
+        let path = "needle-one";
+        let body = b"hello onyxia";
+        let xattr: &[u8] = &[];
+
+        let mut hasher = Crc32Hasher::new();
+        hasher.update(xattr);
+        hasher.update(body);
+        let crc = hasher.finalize();
+
+        let mut record = Vec::new();
+        record.push(NEEDLE_MAGIC);
+        record.push(NEEDLE_VERSION);
+        record.push(Codec::None as u8);
+        record.extend_from_slice(&(path.len() as u16).to_le_bytes());
+        record.extend_from_slice(path.as_bytes());
+        record.extend_from_slice(&(body.len() as u64).to_le_bytes()); // original_length
+        record.extend_from_slice(&(body.len() as u64).to_le_bytes()); // stored_length
+        record.extend_from_slice(&(xattr.len() as u32).to_le_bytes());
+        record.extend_from_slice(xattr);
+        record.extend_from_slice(body);
+        record.extend_from_slice(&crc.to_le_bytes());
+
+        // A well-formed record parses and reports where the next one starts.
+        let mut reader = Cursor::new(record.clone());
+        let (parsed_path, record_offset, record_length, next_offset) =
+            Volume::read_needle_record(&mut reader, 0).unwrap().unwrap();
+        assert_eq!(parsed_path, path);
+        assert_eq!(record_offset, 0);
+        assert_eq!(record_length, record.len() as u64);
+        assert_eq!(next_offset, record.len() as u64);
+
+        // A truncated tail, the shape a crash mid-append leaves behind, is
+        // reported as "nothing usable here" rather than an error, so
+        // rebuild_index can stop cleanly instead of failing outright.
+        let truncated = &record[..record.len() - 5];
+        let mut reader = Cursor::new(truncated.to_vec());
+        assert!(Volume::read_needle_record(&mut reader, 0).unwrap().is_none());
+
+        // A flipped body byte fails the CRC check and is dropped the same
+        // way: not an error, just treated as if nothing were there.
+        let mut corrupted = record.clone();
+        let body_start = corrupted.len() - body.len() - 4;
+        corrupted[body_start] ^= 0xff;
+        let mut reader = Cursor::new(corrupted);
+        assert!(Volume::read_needle_record(&mut reader, 0).unwrap().is_none());
+    }
+
+    #[test]
+    fn compress_decompress_round_trips_every_codec() {
+        use super::{compress, decompress, Codec};
+
+        let data = b"the quick brown fox jumps over the lazy dog ".repeat(200);
+        for codec in [Codec::None, Codec::Zstd, Codec::Lzma] {
+            let stored = compress(codec, &data).unwrap();
+            let restored = decompress(codec, &stored, data.len()).unwrap();
+            assert_eq!(restored, data, "round trip failed for {:?}", codec);
+        }
+    }
+
+    #[test]
+    fn delete_then_compact_then_reopen_forgets_the_tombstoned_key() {
+        use super::Volume;
+        use crate::utils::size::Size;
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut volume = Volume::new(dir.path(), 1, Size::from(1024 * 1024)).unwrap();
+
+        let keep_body = b"still alive".to_vec();
+        volume.write_needle("kept", test_needle(&keep_body)).unwrap();
+        volume.write_needle("doomed", test_needle(b"delete me")).unwrap();
+
+        volume.delete("doomed").unwrap();
+        assert!(volume.get("doomed").is_err());
+        assert_eq!(needle_body(volume.get("kept").unwrap()), keep_body);
+
+        let reclaimed = volume.compact(dir.path()).unwrap();
+        assert!(reclaimed > 0);
+        assert!(!volume.indexes.contains_key("doomed"));
+        assert!(volume.indexes.contains_key("kept"));
+
+        let index_path = dir.path().join("1.index");
+        let reopened = Volume::open(&index_path, Size::from(1024 * 1024)).unwrap();
+        assert!(!reopened.indexes.contains_key("doomed"));
+        assert_eq!(
+            needle_body(reopened.read_needle(reopened.indexes.get("kept").unwrap()).unwrap()),
+            keep_body
+        );
+    }
+
+    #[test]
+    fn legacy_json_index_migrates_and_folds_in_tombstones() {
+        use super::read_legacy_json_index_entries;
+        use super::Index;
+        use std::io::Cursor;
+
+        let mut json = Vec::new();
+        for index in [
+            Index::new("alpha".to_owned(), 7, 0, 10),
+            Index::new("beta".to_owned(), 7, 10, 20),
+        ] {
+            json.extend_from_slice(serde_json::to_string(&index).unwrap().as_bytes());
+        }
+        // A tombstone later in the legacy log must still remove the path it
+        // names, exactly like the binary index format's replay does.
+        json.extend_from_slice(
+            serde_json::to_string(&Index::new("alpha".to_owned(), 7, 0, usize::MAX))
+                .unwrap()
+                .as_bytes(),
+        );
+
+        let (index_map, last_index) = read_legacy_json_index_entries(Cursor::new(json), 7).unwrap();
+        assert!(!index_map.contains_key("alpha"));
+        assert!(index_map.contains_key("beta"));
+        assert_eq!(last_index.offset, 10);
+        assert_eq!(last_index.length, 20);
+    }
+
+    #[test]
+    fn superblock_round_trips_through_a_file_and_reports_a_missing_one_as_none() {
+        use super::{read_superblock, write_superblock, Superblock, DATA_SUPERBLOCK_MAGIC};
+
+        let mut file = tempfile::tempfile().unwrap();
+        write_superblock(
+            &mut file,
+            DATA_SUPERBLOCK_MAGIC,
+            &Superblock {
+                volume_id: 9,
+                max_length: 4096,
+            },
+        )
+        .unwrap();
+
+        let read_back = read_superblock(&mut file, DATA_SUPERBLOCK_MAGIC).unwrap().unwrap();
+        assert_eq!(read_back.volume_id, 9);
+        assert_eq!(read_back.max_length, 4096);
+
+        // A file with no superblock at all (predates the format) reads back
+        // as `None`, not an error, so `open()` can tell it apart from a
+        // genuinely corrupt one.
+        let mut empty = tempfile::tempfile().unwrap();
+        assert!(read_superblock(&mut empty, DATA_SUPERBLOCK_MAGIC).unwrap().is_none());
+    }
+
+    #[test]
+    fn get_many_batches_hits_and_silently_skips_misses() {
+        use super::Volume;
+        use crate::utils::size::Size;
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut volume = Volume::new(dir.path(), 2, Size::from(1024 * 1024)).unwrap();
+
+        volume.write_needle("a", test_needle(b"aaa")).unwrap();
+        volume.write_needle("b", test_needle(b"bbbbb")).unwrap();
+        volume.write_needle("c", test_needle(b"c")).unwrap();
+
+        let mut got = volume.get_many(&["a", "c", "missing"]).unwrap();
+        assert_eq!(got.len(), 2);
+        assert!(!got.contains_key("missing"));
+        assert_eq!(needle_body(got.remove("a").unwrap()), b"aaa");
+        assert_eq!(needle_body(got.remove("c").unwrap()), b"c");
+    }
+
+    #[test]
+    fn encode_decode_attrs_round_trips() {
+        use super::{decode_attrs, encode_attrs};
+        use std::collections::HashMap;
+
+        let mut attrs = HashMap::new();
+        attrs.insert("content-type".to_owned(), b"text/plain".to_vec());
+        attrs.insert("empty".to_owned(), vec![]);
+
+        let encoded = encode_attrs(&attrs).unwrap();
+        let decoded = decode_attrs(&encoded).unwrap();
+        assert_eq!(decoded, attrs);
+    }
+
+    #[test]
+    fn decode_attrs_rejects_a_truncated_block() {
+        use super::decode_attrs;
+
+        // Claims one entry but carries no bytes for it.
+        let truncated = 1u32.to_le_bytes().to_vec();
+        assert!(decode_attrs(&truncated).is_err());
+    }
+
+    #[test]
+    fn write_needle_with_attrs_round_trips_through_getxattr_and_listxattr() {
+        use super::Volume;
+        use crate::utils::size::Size;
+        use std::collections::HashMap;
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut volume = Volume::new(dir.path(), 3, Size::from(1024 * 1024)).unwrap();
+
+        let mut attrs = HashMap::new();
+        attrs.insert("content-type".to_owned(), b"image/png".to_vec());
+        volume
+            .write_needle_with_attrs("with-attrs", test_needle(b"binary data"), attrs)
+            .unwrap();
+
+        assert_eq!(
+            volume.getxattr("with-attrs", "content-type").unwrap(),
+            Some(b"image/png".to_vec())
+        );
+        assert_eq!(
+            volume.listxattr("with-attrs").unwrap(),
+            vec!["content-type".to_owned()]
+        );
+        assert_eq!(volume.getxattr("with-attrs", "missing").unwrap(), None);
+    }
+
+    #[test]
+    fn commit_wal_marks_the_intent_as_applied() {
+        use super::{commit_wal, read_wal_intent, write_wal_intent, Codec, WalIntent};
+
+        let mut wal_file = tempfile::tempfile().unwrap();
+        write_wal_intent(
+            &mut wal_file,
+            &WalIntent {
+                path: "done".to_owned(),
+                offset: 0,
+                length: 5,
+                codec: Codec::Zstd,
+            },
+        )
+        .unwrap();
+        commit_wal(&mut wal_file).unwrap();
+
+        // A committed intent has nothing left to roll back.
+        assert!(read_wal_intent(&mut wal_file).unwrap().is_none());
+    }
+
+    #[test]
+    fn replay_wal_rolls_back_an_intent_whose_index_entry_never_landed() {
+        use super::{read_wal_intent, replay_wal, write_wal_intent, Codec, WalIntent};
+        use crate::index::RawIndex;
+        use std::collections::HashMap;
+        use std::io::Write;
+
+        let mut wal_file = tempfile::tempfile().unwrap();
+        let mut data_file = tempfile::tempfile().unwrap();
+
+        // Data already durable before the write under test began.
+        data_file.write_all(b"existing-data").unwrap();
+        let intent_offset = data_file.metadata().unwrap().len();
+
+        write_wal_intent(
+            &mut wal_file,
+            &WalIntent {
+                path: "crashed".to_owned(),
+                offset: intent_offset,
+                length: 20,
+                codec: Codec::None,
+            },
+        )
+        .unwrap();
+
+        // Simulate a crash partway through the body write: bytes landed past
+        // the intent's offset, but the index entry was never written, so
+        // `indexes` has nothing for "crashed" yet.
+        data_file.write_all(b"half-written-bo").unwrap();
+
+        let mut index_map = HashMap::new();
+        index_map.insert("untouched".to_owned(), RawIndex::new(1, 0, 13));
+
+        replay_wal(&mut wal_file, &mut data_file, &mut index_map).unwrap();
+
+        assert_eq!(data_file.metadata().unwrap().len(), intent_offset);
+        assert!(!index_map.contains_key("crashed"));
+        assert!(index_map.contains_key("untouched"));
+        assert!(read_wal_intent(&mut wal_file).unwrap().is_none());
+    }
+
+    #[test]
+    fn replay_wal_leaves_a_fully_durable_write_intact() {
+        use super::{read_wal_intent, replay_wal, write_wal_intent, Codec, WalIntent};
+        use crate::index::RawIndex;
+        use std::collections::HashMap;
+        use std::io::Write;
+
+        let mut wal_file = tempfile::tempfile().unwrap();
+        let mut data_file = tempfile::tempfile().unwrap();
+
+        data_file.write_all(b"existing-data").unwrap();
+        let intent_offset = data_file.metadata().unwrap().len();
+        data_file.write_all(b"fully-written-body-and-crc").unwrap();
+        let final_length = data_file.metadata().unwrap().len();
+
+        write_wal_intent(
+            &mut wal_file,
+            &WalIntent {
+                path: "done-but-uncommitted".to_owned(),
+                offset: intent_offset,
+                length: final_length - intent_offset,
+                codec: Codec::None,
+            },
+        )
+        .unwrap();
+
+        // The body and its index entry both made it to disk before the
+        // crash hit; only the WAL's own commit-marker fsync was lost.
+        let mut index_map = HashMap::new();
+        index_map.insert(
+            "done-but-uncommitted".to_owned(),
+            RawIndex::new(1, intent_offset as usize, (final_length - intent_offset) as usize),
+        );
+
+        replay_wal(&mut wal_file, &mut data_file, &mut index_map).unwrap();
+
+        // Nothing gets truncated or dropped: the write already completed.
+        assert_eq!(data_file.metadata().unwrap().len(), final_length);
+        assert!(index_map.contains_key("done-but-uncommitted"));
+        assert!(read_wal_intent(&mut wal_file).unwrap().is_none());
+    }
 }
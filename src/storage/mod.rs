@@ -1,4 +1,5 @@
 use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
 use std::path::{Path, PathBuf};
 
 use async_std::sync::{Arc, RwLock};
@@ -12,3 +13,74 @@ pub mod index;
 pub mod service;
 pub mod storage;
 pub mod volume;
+
+/// Thread-safe, clonable handle to a [`Volume`] for sharing across async
+/// tasks. [`AsyncVolume::get`]/[`AsyncVolume::get_many`] take a shared lock
+/// just long enough to resolve the read against `indexes`, so they can run
+/// concurrently with each other. `AsyncVolume` is read-only: it exposes no
+/// write path and no way to reach the wrapped [`Volume`] to call one
+/// directly, so a volume that needs further writes must be mutated before
+/// it's wrapped.
+#[derive(Clone)]
+pub struct AsyncVolume {
+    inner: Arc<RwLock<Volume>>,
+}
+
+impl AsyncVolume {
+    pub fn new(volume: Volume) -> AsyncVolume {
+        AsyncVolume {
+            inner: Arc::new(RwLock::new(volume)),
+        }
+    }
+
+    /// Async counterpart to [`Volume::get`]. The shared lock is only held
+    /// long enough to resolve `path` against `indexes` and clone a handle
+    /// onto the `.data` file — both cheap, non-blocking operations — so the
+    /// lock is never held for the duration of a disk read.
+    /// The actual read runs on async-std's blocking thread pool once the lock
+    /// has already been released.
+    pub async fn get<K>(&self, path: K) -> Result<Needle>
+    where
+        K: Into<String> + Display,
+    {
+        let path = path.into();
+        let (file, index) = {
+            let volume = self.inner.read().await;
+            let index = volume.resolve_index(&path)?;
+            (volume.readonly_volume.try_clone()?, index)
+        };
+        async_std::task::spawn_blocking(move || Volume::read_needle_from_file(&file, &index)).await
+    }
+
+    /// Async counterpart to [`Volume::get_many`]. See [`AsyncVolume::get`]
+    /// for the locking/offloading strategy; resolution of every path happens
+    /// under the lock, the ascending-offset sort and the reads themselves
+    /// after it's released.
+    pub async fn get_many<K>(&self, paths: &[K]) -> Result<HashMap<String, Needle>>
+    where
+        K: Into<String> + Display + Clone,
+    {
+        let (file, mut hits) = {
+            let volume = self.inner.read().await;
+            let mut hits = Vec::with_capacity(paths.len());
+            for path in paths {
+                let path: String = path.clone().into();
+                if volume.indexes.contains_key(&path) {
+                    hits.push((path.clone(), volume.resolve_index(&path)?));
+                }
+            }
+            (volume.readonly_volume.try_clone()?, hits)
+        };
+        hits.sort_by_key(|(_, index)| index.offset);
+
+        async_std::task::spawn_blocking(move || {
+            let mut needles = HashMap::with_capacity(hits.len());
+            for (path, index) in hits.drain(..) {
+                let needle = Volume::read_needle_from_file(&file, &index)?;
+                needles.insert(path, needle);
+            }
+            Ok(needles)
+        })
+        .await
+    }
+}